@@ -51,6 +51,22 @@ pub enum NodeStatus {
     Bad,
 }
 
+/// Timer granularity: no ping interval or response timeout is computed below
+/// this value.
+const RTT_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// How many ping intervals of silence are tolerated before a node with a known
+/// RTT is considered `Bad`. Must be at least two so a responsive node is never
+/// declared `Bad` between its own pings.
+const RESPONSE_TIMEOUT_PINGS: u32 = 4;
+
+/// Base number of round-trip times between pings, scaled up the longer a node
+/// keeps answering.
+const PING_INTERVAL_RTTS: u32 = 8;
+
+/// Largest ping back-off factor applied to consistently responsive nodes.
+const MAX_PING_BACKOFF: u32 = 8;
+
 /// check distance of PK1 and PK2 from base_PK including status of node
 pub trait ReplaceOrder {
     /// Check distance of PK1 and Pk2 including status of node
@@ -68,7 +84,13 @@ impl ReplaceOrder for PublicKey {
             NodeStatus::Good => {
                 match node2.calc_status(bad_node_timeout) {
                     NodeStatus::Good => { // Good, Good
-                        self.distance(&node1.pk, &node2.pk)
+                        // Among equally-Good nodes, the one with the higher RTT
+                        // is the better replacement candidate; fall back to PK
+                        // distance when their latencies are indistinguishable.
+                        match node1.rtt_order(node2) {
+                            Ordering::Equal => self.distance(&node1.pk, &node2.pk),
+                            order => order,
+                        }
                     },
                     NodeStatus::Bad => { // Good, Bad
                         Ordering::Less // Good is closer
@@ -108,6 +130,13 @@ pub struct DhtNode {
     pub last_resp_time: Instant,
     /// last sent ping-req time
     pub last_ping_req_time: Instant,
+    /// smoothed round-trip time, `None` until the first ping is answered
+    pub srtt: Option<Duration>,
+    /// round-trip time variation
+    pub rttvar: Duration,
+    /// number of consecutive pings answered, used to back off ping scheduling
+    /// for reliably responsive nodes
+    pub ping_success_streak: u32,
 }
 
 impl DhtNode {
@@ -119,18 +148,96 @@ impl DhtNode {
             ping_hash: HashMap::new(),
             last_resp_time: Instant::now(),
             last_ping_req_time: Instant::now(),
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            ping_success_streak: 0,
         }
     }
 
     /// calc. status of node
+    ///
+    /// When the node's round-trip time is known the decision is driven by its
+    /// own latency profile — a node silent for more than a few of its own ping
+    /// intervals, themselves multiples of its smoothed RTT, is `Bad` — bounded
+    /// by the global `bad_node_timeout`. Nodes without
+    /// an RTT sample yet fall back to the global timeout alone.
     pub fn calc_status(&self, bad_node_timeout: Duration) -> NodeStatus {
-        if self.last_resp_time.elapsed() > bad_node_timeout {
+        if self.last_resp_time.elapsed() > self.response_timeout(bad_node_timeout) {
             NodeStatus::Bad
         } else {
             NodeStatus::Good
         }
     }
 
+    /// RTT-derived silence tolerance, capped by the global `bad_node_timeout`.
+    ///
+    /// The budget is a small multiple of the node's own ping interval so that a
+    /// node answering on schedule is not flipped to `Bad` in the gap between
+    /// two of its pings, as long as that interval stays below `bad_node_timeout`
+    /// (the cap applies first); nodes without an RTT sample yet use the global
+    /// timeout.
+    fn response_timeout(&self, bad_node_timeout: Duration) -> Duration {
+        match self.srtt {
+            Some(_) => {
+                let budget = self.next_ping_interval(bad_node_timeout) * RESPONSE_TIMEOUT_PINGS;
+                min_duration(max_duration(budget, RTT_GRANULARITY), bad_node_timeout)
+            },
+            None => bad_node_timeout,
+        }
+    }
+
+    /// Fold a fresh round-trip time `sample` into the smoothed estimate. The
+    /// first sample seeds `srtt`/`rttvar`; later samples use
+    /// `rttvar = 3/4*rttvar + 1/4*|srtt - sample|` and
+    /// `srtt = 7/8*srtt + 1/8*sample`.
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            },
+            Some(srtt) => {
+                let diff = if srtt > sample { srtt - sample } else { sample - srtt };
+                self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            },
+        }
+    }
+
+    /// Interval to wait before sending the next ping to this node.
+    ///
+    /// The interval is a multiple of the smoothed RTT, stretched further the
+    /// longer the node has answered consecutively so that reliably responsive
+    /// nodes are probed less often, while a flaky node — whose streak resets
+    /// whenever `check_ping_id` sees a ping answered after its timeout — is
+    /// probed sooner. Pings that are never answered and are dropped by
+    /// `clear_timedout_pings` do not reset the streak. Nodes without an RTT
+    /// sample yet use the caller-provided `base_interval`.
+    ///
+    /// This interval is also the unit of the node's liveness budget: `calc_status`
+    /// (via `response_timeout`) tolerates a few of these intervals of silence
+    /// before declaring the node `Bad`.
+    pub fn next_ping_interval(&self, base_interval: Duration) -> Duration {
+        match self.srtt {
+            Some(srtt) => {
+                let backoff = (1 + self.ping_success_streak).min(MAX_PING_BACKOFF);
+                max_duration(srtt * PING_INTERVAL_RTTS * backoff, RTT_GRANULARITY)
+            },
+            None => base_interval,
+        }
+    }
+
+    /// Ordering that ranks a higher-RTT node as the better replacement
+    /// candidate (`Greater`). Nodes without an RTT sample sort as if slowest.
+    fn rtt_order(&self, other: &DhtNode) -> Ordering {
+        match (self.srtt, other.srtt) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
     /// set new random ping id to the client and return it
     fn generate_ping_id(&mut self) -> u64 {
         loop {
@@ -170,15 +277,32 @@ impl DhtNode {
             Some(time) => time,
         };
 
-        if time_ping_sent.elapsed() > timeout {
+        let sample = time_ping_sent.elapsed();
+        if sample > timeout {
             debug!("Given ping_id is timed out");
+            // A missed ping means the node is less reliable than its streak
+            // suggested, so probe it sooner next time.
+            self.ping_success_streak = 0;
             return false
         }
 
+        self.update_rtt(sample);
+        self.ping_success_streak = self.ping_success_streak.saturating_add(1);
+
         true
     }
 }
 
+/// Smaller of two `Duration`s.
+fn min_duration(a: Duration, b: Duration) -> Duration {
+    if a <= b { a } else { b }
+}
+
+/// Larger of two `Duration`s.
+fn max_duration(a: Duration, b: Duration) -> Duration {
+    if a >= b { a } else { b }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -255,6 +379,52 @@ mod tests {
         assert!(client.check_ping_id(ping_id, dur));
     }
 
+    #[test]
+    fn client_data_rtt_updated_on_successful_ping() {
+        let pn = PackedNode {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:33445".parse().unwrap(),
+        };
+        let mut client = DhtNode::new(pn);
+
+        assert!(client.srtt.is_none());
+        let ping_id = client.insert_new_ping_id();
+        assert!(client.check_ping_id(ping_id, Duration::from_secs(5)));
+        // A matched ping seeds the smoothed RTT and bumps the success streak.
+        assert!(client.srtt.is_some());
+        assert_eq!(client.ping_success_streak, 1);
+    }
+
+    #[test]
+    fn client_data_missed_ping_resets_streak() {
+        let pn = PackedNode {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:33445".parse().unwrap(),
+        };
+        let mut client = DhtNode::new(pn);
+
+        let ping_id = client.insert_new_ping_id();
+        assert!(client.check_ping_id(ping_id, Duration::from_secs(5)));
+        assert_eq!(client.ping_success_streak, 1);
+
+        // A timed-out ping resets the streak back to zero.
+        let ping_id = client.insert_new_ping_id();
+        assert!(!client.check_ping_id(ping_id, Duration::from_secs(0)));
+        assert_eq!(client.ping_success_streak, 0);
+    }
+
+    #[test]
+    fn client_data_next_ping_interval_falls_back_without_rtt() {
+        let pn = PackedNode {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:33445".parse().unwrap(),
+        };
+        let client = DhtNode::new(pn);
+
+        let base = Duration::from_secs(60);
+        assert_eq!(client.next_ping_interval(base), base);
+    }
+
     #[test]
     fn dht_node_bucket_try_add_test() {
         fn with_nodes(n1: PackedNode, n2: PackedNode, n3: PackedNode,
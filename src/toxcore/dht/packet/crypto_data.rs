@@ -31,7 +31,7 @@ use toxcore::crypto_core::*;
 
 /// The maximum size of `CryptoData` packet including two bytes of nonce and
 /// packet kind byte.
-const MAX_CRYPTO_PACKET_SIZE: usize = 1400;
+pub const MAX_CRYPTO_PACKET_SIZE: usize = 1400;
 
 /** Packet used to send data over `net_crypto` connection.
 
@@ -0,0 +1,403 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Loss detection and congestion control for `CryptoData` streams.
+
+`CryptoDataPayload` carries `buffer_start` (highest handled packet number + 1)
+and `packet_number`, which is exactly the raw material needed for reliable,
+ordered delivery. `buffer_start` is a *cumulative* ack: every packet below it
+has been handled by the peer. The payload carries no selective-ack field, so
+the packet-threshold test of RFC 9002 (which needs "the largest acked packet"
+distinct from the cumulative point) has no wire source and is not used; loss
+detection is driven by the RFC 9002 time-threshold alone. A NewReno congestion
+controller gates new `CryptoData::new` sends on the available congestion window.
+*/
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use toxcore::dht::packet::crypto_data::MAX_CRYPTO_PACKET_SIZE;
+
+/// Timer granularity. No timeout is ever scheduled below this value.
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Time-threshold multiplier applied to the RTT before an unacknowledged
+/// packet is declared lost, expressed as `TIME_THRESHOLD_NUM / TIME_THRESHOLD_DEN`
+/// (`9/8`). Tolerates reordering without spuriously retransmitting.
+const TIME_THRESHOLD_NUM: u32 = 9;
+const TIME_THRESHOLD_DEN: u32 = 8;
+
+/// Initial smoothed RTT used until the first sample arrives.
+const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+/// Maximum segment size: the largest amount of `CryptoData` payload that can
+/// travel in a single packet.
+const MSS: usize = MAX_CRYPTO_PACKET_SIZE;
+
+/// Initial congestion window in bytes.
+const INITIAL_CWND: usize = 10 * MSS;
+
+/// Smallest congestion window we are willing to shrink to.
+const MINIMUM_CWND: usize = 2 * MSS;
+
+/// Smoothed round-trip time estimator shared by loss detection and the probe
+/// timeout, maintained exactly as described in RFC 9002 §5.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RttEstimate {
+    /// Smoothed RTT.
+    srtt: Duration,
+    /// RTT variation.
+    rttvar: Duration,
+    /// Whether at least one sample has been folded in.
+    has_sample: bool,
+}
+
+impl Default for RttEstimate {
+    fn default() -> RttEstimate {
+        RttEstimate {
+            srtt: INITIAL_RTT,
+            rttvar: INITIAL_RTT / 2,
+            has_sample: false,
+        }
+    }
+}
+
+impl RttEstimate {
+    /// Fold a new RTT sample in. The first sample seeds `srtt`/`rttvar`
+    /// directly; subsequent samples use the classic EWMA
+    /// `rttvar = 3/4*rttvar + 1/4*|srtt - sample|` and
+    /// `srtt = 7/8*srtt + 1/8*sample`.
+    pub fn add_sample(&mut self, sample: Duration) {
+        if !self.has_sample {
+            self.srtt = sample;
+            self.rttvar = sample / 2;
+            self.has_sample = true;
+            return;
+        }
+
+        let diff = if self.srtt > sample {
+            self.srtt - sample
+        } else {
+            sample - self.srtt
+        };
+        self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+        self.srtt = self.srtt * 7 / 8 + sample / 8;
+    }
+
+    /// Current smoothed RTT.
+    pub fn srtt(&self) -> Duration {
+        self.srtt
+    }
+
+    /// Loss delay `max(srtt + 4*rttvar, granularity) * time_threshold`: once
+    /// the peer has acknowledged anything, an in-flight packet older than this
+    /// is lost.
+    pub fn loss_delay(&self) -> Duration {
+        let base = cmp_max(self.srtt + 4 * self.rttvar, GRANULARITY);
+        base * TIME_THRESHOLD_NUM / TIME_THRESHOLD_DEN
+    }
+
+    /// Base probe timeout `srtt + 4*rttvar + granularity` before back-off.
+    pub fn pto(&self) -> Duration {
+        self.srtt + 4 * self.rttvar + GRANULARITY
+    }
+}
+
+/// Book-keeping for a single packet still in flight.
+#[derive(Clone, Copy, Debug)]
+struct SentPacket {
+    /// When the packet was handed to the socket.
+    send_time: Instant,
+    /// Size in bytes counted against the congestion window.
+    size: usize,
+}
+
+/// What happened to a packet as a result of processing an ack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LossEvent {
+    /// The packet was newly acknowledged.
+    Acked(u32),
+    /// The packet was declared lost and should be retransmitted.
+    Lost(u32),
+}
+
+/// Reliable, congestion-aware state for a single `net_crypto` stream.
+///
+/// Record every send with [`on_sent`](CongestionControl::on_sent), feed the
+/// peer's cumulative `buffer_start` ack into
+/// [`on_ack`](CongestionControl::on_ack), and ask
+/// [`can_send`](CongestionControl::can_send) before building the next
+/// `CryptoData`.
+#[derive(Clone, Debug)]
+pub struct CongestionControl {
+    /// In-flight packets keyed by their packet number.
+    sent: HashMap<u32, SentPacket>,
+    /// Round-trip time estimator.
+    rtt: RttEstimate,
+    /// Congestion window in bytes.
+    cwnd: usize,
+    /// Slow-start threshold in bytes.
+    ssthresh: usize,
+    /// Bytes currently in flight.
+    bytes_in_flight: usize,
+    /// Highest packet number the peer has acknowledged.
+    largest_acked: Option<u32>,
+    /// Consecutive probe-timeout firings, used for exponential back-off.
+    pto_count: u32,
+}
+
+impl Default for CongestionControl {
+    fn default() -> CongestionControl {
+        CongestionControl {
+            sent: HashMap::new(),
+            rtt: RttEstimate::default(),
+            cwnd: INITIAL_CWND,
+            ssthresh: usize::max_value(),
+            bytes_in_flight: 0,
+            largest_acked: None,
+            pto_count: 0,
+        }
+    }
+}
+
+impl CongestionControl {
+    /// Create a fresh controller in slow start.
+    pub fn new() -> CongestionControl {
+        CongestionControl::default()
+    }
+
+    /// Whether a new packet of `size` bytes may be sent without exceeding the
+    /// congestion window.
+    pub fn can_send(&self, size: usize) -> bool {
+        self.bytes_in_flight + size <= self.cwnd
+    }
+
+    /// Current congestion window in bytes.
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Bytes currently in flight.
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    /// Current RTT estimate.
+    pub fn rtt(&self) -> RttEstimate {
+        self.rtt
+    }
+
+    /// Record a freshly sent packet.
+    pub fn on_sent(&mut self, packet_number: u32, size: usize, now: Instant) {
+        self.bytes_in_flight += size;
+        self.sent.insert(packet_number, SentPacket {
+            send_time: now,
+            size,
+        });
+    }
+
+    /// Process the cumulative ack carried in a peer's `CryptoDataPayload`.
+    ///
+    /// `buffer_start` is the peer's highest handled packet number + 1, so every
+    /// in-flight packet below it is acknowledged and the largest acknowledged
+    /// packet number is `buffer_start - 1`. Returns the resulting ack/loss
+    /// events, the acked ones first.
+    pub fn on_ack(&mut self, buffer_start: u32, now: Instant) -> Vec<LossEvent> {
+        let mut events = Vec::new();
+
+        // `buffer_start == 0` means the peer has handled nothing yet: there is
+        // no acknowledgement to process and nothing in flight can be below it.
+        if buffer_start == 0 {
+            return events;
+        }
+
+        // The cumulative ack point is the only ack information on the wire.
+        let largest = buffer_start - 1;
+        self.largest_acked = Some(self.largest_acked.map_or(largest, |l| l.max(largest)));
+
+        // Everything below `buffer_start` has been handled by the peer.
+        let acked: Vec<u32> = self.sent.keys().cloned().filter(|&pn| pn < buffer_start).collect();
+        for pn in acked {
+            if let Some(packet) = self.sent.remove(&pn) {
+                self.bytes_in_flight -= packet.size;
+                // Only the most recent sample keeps the estimator responsive.
+                if pn == largest {
+                    self.rtt.add_sample(now.duration_since(packet.send_time));
+                }
+                self.on_ack_cwnd(packet.size);
+                events.push(LossEvent::Acked(pn));
+            }
+        }
+
+        // A fresh ack resets the probe back-off.
+        self.pto_count = 0;
+
+        events.extend(self.detect_lost(now));
+        events
+    }
+
+    /// Declare stragglers lost by a cumulative-ack adaptation of the RFC 9002
+    /// time-threshold: with no selective-ack information, once the peer has
+    /// acknowledged progress any still-in-flight packet older than the loss
+    /// delay is treated as lost and scheduled for retransmission.
+    fn detect_lost(&mut self, now: Instant) -> Vec<LossEvent> {
+        if self.largest_acked.is_none() {
+            return Vec::new();
+        }
+
+        let loss_delay = self.rtt.loss_delay();
+        let lost: Vec<u32> = self.sent.iter().filter(|&(_, packet)| {
+            now.duration_since(packet.send_time) >= loss_delay
+        }).map(|(&pn, _)| pn).collect();
+
+        if lost.is_empty() {
+            return Vec::new();
+        }
+
+        let mut events = Vec::with_capacity(lost.len());
+        for pn in &lost {
+            if let Some(packet) = self.sent.remove(pn) {
+                self.bytes_in_flight -= packet.size;
+                events.push(LossEvent::Lost(*pn));
+            }
+        }
+        self.on_congestion_event();
+        events
+    }
+
+    /// Grow the congestion window on a good ack: one MSS per ack in slow start,
+    /// additive increase afterwards.
+    fn on_ack_cwnd(&mut self, acked: usize) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += MSS;
+        } else {
+            self.cwnd += MSS * acked / self.cwnd.max(1);
+        }
+    }
+
+    /// Halve the window and enter congestion avoidance after a loss.
+    fn on_congestion_event(&mut self) {
+        self.ssthresh = (self.cwnd / 2).max(MINIMUM_CWND);
+        self.cwnd = self.ssthresh;
+    }
+
+    /// Probe timeout after which, absent any ack, the oldest in-flight packets
+    /// should be retransmitted. Backs off exponentially on consecutive firings.
+    pub fn pto(&self) -> Duration {
+        self.rtt.pto() * 2u32.pow(self.pto_count.min(16))
+    }
+
+    /// Mark that the probe timeout fired, arming the exponential back-off for
+    /// the next one, and return the in-flight packet numbers to retransmit.
+    pub fn on_pto(&mut self) -> Vec<u32> {
+        self.pto_count = self.pto_count.saturating_add(1);
+        self.sent.keys().cloned().collect()
+    }
+}
+
+/// `Duration` has no stable `max` on the targeted toolchain, so compare by hand.
+fn cmp_max(a: Duration, b: Duration) -> Duration {
+    if a >= b {
+        a
+    } else {
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_first_sample_seeds_estimator() {
+        let mut rtt = RttEstimate::default();
+        rtt.add_sample(Duration::from_millis(100));
+        assert_eq!(rtt.srtt(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn rtt_smooths_subsequent_samples() {
+        let mut rtt = RttEstimate::default();
+        rtt.add_sample(Duration::from_millis(100));
+        rtt.add_sample(Duration::from_millis(200));
+        // srtt = 7/8*100 + 1/8*200 = 112.5ms
+        assert_eq!(rtt.srtt(), Duration::from_millis(112) + Duration::from_micros(500));
+    }
+
+    #[test]
+    fn cwnd_grows_in_slow_start() {
+        let mut cc = CongestionControl::new();
+        let start = cc.cwnd();
+        let now = Instant::now();
+        cc.on_sent(0, MSS, now);
+        cc.on_ack(1, now);
+        assert_eq!(cc.cwnd(), start + MSS);
+    }
+
+    #[test]
+    fn time_threshold_declares_loss() {
+        let mut cc = CongestionControl::new();
+        let now = Instant::now();
+        for pn in 0..5 {
+            cc.on_sent(pn, MSS, now);
+        }
+        // Cumulatively ack 0..=3; packet 4 stays in flight.
+        cc.on_ack(4, now);
+        // Long after the loss delay, with an ack already seen, the straggler
+        // is declared lost.
+        let later = now + Duration::from_secs(10);
+        let events = cc.on_ack(4, later);
+        assert!(events.contains(&LossEvent::Lost(4)));
+    }
+
+    #[test]
+    fn loss_halves_window() {
+        let mut cc = CongestionControl::new();
+        let now = Instant::now();
+        for pn in 0..5 {
+            cc.on_sent(pn, MSS, now);
+        }
+        cc.on_ack(4, now);
+        let before = cc.cwnd();
+        // Packet 4 times out well past the loss delay, halving the window.
+        let later = now + Duration::from_secs(10);
+        cc.on_ack(4, later);
+        assert!(cc.cwnd() <= before / 2 + MSS);
+    }
+
+    #[test]
+    fn can_send_respects_window() {
+        let mut cc = CongestionControl::new();
+        let now = Instant::now();
+        assert!(cc.can_send(MSS));
+        for pn in 0..10 {
+            cc.on_sent(pn, MSS, now);
+        }
+        assert!(!cc.can_send(MSS));
+    }
+
+    #[test]
+    fn pto_backs_off_exponentially() {
+        let mut cc = CongestionControl::new();
+        let base = cc.pto();
+        cc.on_pto();
+        assert_eq!(cc.pto(), base * 2);
+    }
+}
@@ -0,0 +1,209 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Automatic session rekeying for `net_crypto` connections.
+
+A connection built from a single `PrecomputedKey` lives its whole lifetime on
+one session key with a monotonically advancing nonce, so the amount of data
+under that key is unbounded. `KeySchedule` periodically swaps in a freshly
+negotiated key — after a configured number of packets, a configured lifetime,
+or as the nonce counter approaches wraparound — bounding the data sealed under
+any one key and giving long-lived connections forward secrecy.
+
+In-flight packets sealed under the previous key are tolerated by keeping that
+key for an overlap window: decryption is attempted under the current key first
+and falls back to the previous key on failure. This keeps the `CryptoData` wire
+format unchanged — no epoch byte rides on the payload, so peers that never rekey
+interoperate unmodified. The previous key is retired once a packet decrypts
+under the current key, confirming the peer has adopted it. The epoch counter is
+kept purely as local book-keeping to identify the active key.
+*/
+
+use std::io::Error;
+use std::time::{Duration, Instant};
+
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::crypto_data::*;
+
+/// Nonce counter value past which rekeying is forced to avoid wrapping the low
+/// 16 bits that travel in `nonce_last_bytes`.
+const NONCE_REKEY_THRESHOLD: u16 = u16::max_value() - 512;
+
+/// Limits on how much a single session key may be used before it is rotated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyConfig {
+    /// Rekey after this many packets have been sealed under the current key.
+    pub max_packets: u64,
+    /// Rekey once the current key is older than this.
+    pub max_lifetime: Duration,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> RekeyConfig {
+        RekeyConfig {
+            max_packets: 1 << 20,
+            max_lifetime: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// A session key together with its epoch byte.
+#[derive(Clone)]
+struct EpochKey {
+    epoch: u8,
+    key: PrecomputedKey,
+}
+
+/// Current and (during the overlap window) previous session keys for a single
+/// connection, rotated according to a [`RekeyConfig`].
+#[derive(Clone)]
+pub struct KeySchedule {
+    current: EpochKey,
+    previous: Option<EpochKey>,
+    config: RekeyConfig,
+    packets_under_current: u64,
+    current_since: Instant,
+}
+
+impl KeySchedule {
+    /// Start a schedule on `key` at epoch 0.
+    pub fn new(key: PrecomputedKey, config: RekeyConfig) -> KeySchedule {
+        KeySchedule {
+            current: EpochKey { epoch: 0, key },
+            previous: None,
+            config,
+            packets_under_current: 0,
+            current_since: Instant::now(),
+        }
+    }
+
+    /// Epoch byte of the current key.
+    pub fn current_epoch(&self) -> u8 {
+        self.current.epoch
+    }
+
+    /// Whether the current key should be rotated, given the nonce that would be
+    /// used for the next send.
+    pub fn should_rekey(&self, next_nonce: Nonce) -> bool {
+        self.packets_under_current >= self.config.max_packets
+            || self.current_since.elapsed() >= self.config.max_lifetime
+            || CryptoData::nonce_last_bytes(next_nonce) >= NONCE_REKEY_THRESHOLD
+    }
+
+    /// Rotate to a freshly negotiated `key`, moving the current key into the
+    /// overlap window so that in-flight packets still decrypt.
+    pub fn rekey(&mut self, key: PrecomputedKey) {
+        let epoch = self.current.epoch.wrapping_add(1);
+        let old = ::std::mem::replace(&mut self.current, EpochKey { epoch, key });
+        self.previous = Some(old);
+        self.packets_under_current = 0;
+        self.current_since = Instant::now();
+    }
+
+    /// Seal `data` into a `CryptoData` under the current key, accounting it
+    /// against the rekey budget.
+    pub fn seal(&mut self, nonce: Nonce, buffer_start: u32, packet_number: u32, data: Vec<u8>) -> CryptoData {
+        let payload = CryptoDataPayload {
+            buffer_start,
+            packet_number,
+            data,
+        };
+        self.packets_under_current = self.packets_under_current.saturating_add(1);
+        CryptoData::new(&self.current.key, nonce, payload)
+    }
+
+    /** Open a received `CryptoData`, trying the current key first and falling
+    back to the previous key during the overlap window.
+
+    Returns `Error` only when the packet decrypts under neither key. A packet
+    that decrypts under the current key confirms the peer has adopted it and
+    retires the previous key.
+    */
+    pub fn open(&mut self, packet: &CryptoData, nonce: &Nonce) -> Result<CryptoDataPayload, Error> {
+        match packet.get_payload(&self.current.key, nonce) {
+            Ok(payload) => {
+                // A packet under the current key confirms the rekey; drop the old key.
+                self.previous = None;
+                Ok(payload)
+            },
+            Err(err) => match self.previous {
+                Some(ref previous) => packet.get_payload(&previous.key, nonce),
+                None => Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared_key() -> PrecomputedKey {
+        let (pk, _) = gen_keypair();
+        let (_, sk) = gen_keypair();
+        encrypt_precompute(&pk, &sk)
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let key = shared_key();
+        let mut schedule = KeySchedule::new(key, RekeyConfig::default());
+        let nonce = gen_nonce();
+        let packet = schedule.seal(nonce, 1, 2, vec![42; 10]);
+        let payload = schedule.open(&packet, &nonce).unwrap();
+        assert_eq!(payload.data, vec![42; 10]);
+    }
+
+    #[test]
+    fn should_rekey_after_max_packets() {
+        let config = RekeyConfig { max_packets: 1, max_lifetime: Duration::from_secs(3600) };
+        let mut schedule = KeySchedule::new(shared_key(), config);
+        // Low nonce bytes far from wraparound, so only the packet count matters.
+        let nonce = Nonce([0; NONCEBYTES]);
+        assert!(!schedule.should_rekey(nonce));
+        schedule.seal(nonce, 0, 0, vec![0; 1]);
+        assert!(schedule.should_rekey(nonce));
+    }
+
+    #[test]
+    fn previous_key_decrypts_during_overlap() {
+        let mut schedule = KeySchedule::new(shared_key(), RekeyConfig::default());
+        let nonce = gen_nonce();
+        // Seal under epoch 0, then rekey before the packet is opened.
+        let old_packet = schedule.seal(nonce, 0, 0, vec![7; 4]);
+        schedule.rekey(shared_key());
+        assert_eq!(schedule.current_epoch(), 1);
+        // The packet no longer opens under the current key, so the overlap
+        // window lets it fall back to the previous key.
+        let payload = schedule.open(&old_packet, &nonce).unwrap();
+        assert_eq!(payload.data, vec![7; 4]);
+    }
+
+    #[test]
+    fn current_epoch_packet_retires_previous_key() {
+        let mut schedule = KeySchedule::new(shared_key(), RekeyConfig::default());
+        let nonce = gen_nonce();
+        schedule.rekey(shared_key());
+        let new_packet = schedule.seal(nonce, 0, 0, vec![1; 4]);
+        assert!(schedule.previous.is_some());
+        schedule.open(&new_packet, &nonce).unwrap();
+        assert!(schedule.previous.is_none());
+    }
+}
@@ -0,0 +1,181 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Trusted-key-set keying for `net_crypto`.
+
+`CryptoData` is normally built from a single `PrecomputedKey` derived from one
+peer's public key, which restricts a node to a strictly pairwise relationship.
+`TrustSet` generalises that to a *set* of authorized identities: a node can be
+reached by any of several trusted public keys without per-peer reconfiguration,
+and the set may be populated from a shared passphrase so that every node that
+knows the passphrase derives — and trusts — the same identity. This supports
+both multi-identity acceptance and group-style deployments around one secret.
+
+On send, pick the precomputed key matching the chosen peer; on receive, try each
+trusted key in turn and report which identity decrypted the packet.
+*/
+
+use std::io::{Error, ErrorKind};
+
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::crypto_data::*;
+
+/// One authorized identity together with the key precomputed against our own
+/// secret key.
+#[derive(Clone)]
+struct TrustedKey {
+    identity: PublicKey,
+    precomputed: PrecomputedKey,
+}
+
+/// Deterministically derive a key pair from a passphrase.
+///
+/// Every node that knows `passphrase` derives the same secret key (and thus the
+/// same public identity), so a single shared secret can authorize a whole group.
+pub fn derive_keypair_from_passphrase(passphrase: &[u8]) -> (PublicKey, SecretKey) {
+    use ::sodiumoxide::crypto::hash::sha256;
+    use ::sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, Scalar};
+
+    let digest = sha256::hash(passphrase);
+    let secret = SecretKey::from_slice(&digest.0)
+        .expect("sha256 digest is exactly the secret key length");
+    let scalar = Scalar::from_slice(&digest.0)
+        .expect("sha256 digest is exactly the scalar length");
+    let public = PublicKey::from_slice(&scalarmult_base(&scalar).0)
+        .expect("scalarmult_base yields a valid public key");
+    (public, secret)
+}
+
+/// A set of authorized identities reachable with our own secret key.
+#[derive(Clone)]
+pub struct TrustSet {
+    own_sk: SecretKey,
+    keys: Vec<TrustedKey>,
+}
+
+impl TrustSet {
+    /// Create an empty trust set keyed on our own secret key.
+    pub fn new(own_sk: SecretKey) -> TrustSet {
+        TrustSet {
+            own_sk,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Trust `identity`, precomputing the shared key against our secret key.
+    pub fn trust(&mut self, identity: PublicKey) {
+        if self.keys.iter().any(|k| k.identity == identity) {
+            return;
+        }
+        let precomputed = encrypt_precompute(&identity, &self.own_sk);
+        self.keys.push(TrustedKey { identity, precomputed });
+    }
+
+    /// Trust the identity derived from a shared passphrase and return it.
+    pub fn trust_shared_secret(&mut self, passphrase: &[u8]) -> PublicKey {
+        let (identity, _) = derive_keypair_from_passphrase(passphrase);
+        self.trust(identity);
+        identity
+    }
+
+    /// Whether `identity` is in the trust set.
+    pub fn is_trusted(&self, identity: &PublicKey) -> bool {
+        self.keys.iter().any(|k| &k.identity == identity)
+    }
+
+    /// The precomputed key for sending to `peer`, if it is trusted.
+    pub fn key_for(&self, peer: &PublicKey) -> Option<&PrecomputedKey> {
+        self.keys.iter().find(|k| &k.identity == peer).map(|k| &k.precomputed)
+    }
+
+    /** Open a received `CryptoData` against every trusted key.
+
+    Returns the identity that decrypted the packet together with its payload, or
+    `Error` when no trusted key decrypts it.
+    */
+    pub fn open(&self, packet: &CryptoData, nonce: &Nonce) -> Result<(PublicKey, CryptoDataPayload), Error> {
+        for key in &self.keys {
+            if let Ok(payload) = packet.get_payload(&key.precomputed, nonce) {
+                return Ok((key.identity, payload));
+            }
+        }
+        Err(Error::new(ErrorKind::Other, "No trusted key could decrypt CryptoData."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_derivation_is_deterministic() {
+        let (pk1, _) = derive_keypair_from_passphrase(b"correct horse battery staple");
+        let (pk2, _) = derive_keypair_from_passphrase(b"correct horse battery staple");
+        assert_eq!(pk1, pk2);
+    }
+
+    #[test]
+    fn open_identifies_sender() {
+        // Our node holds a secret key; a peer seals a packet to our public key.
+        let (our_pk, our_sk) = gen_keypair();
+        let (peer_pk, peer_sk) = gen_keypair();
+        let mut trust = TrustSet::new(our_sk);
+        trust.trust(peer_pk);
+
+        let shared = encrypt_precompute(&our_pk, &peer_sk);
+        let nonce = gen_nonce();
+        let payload = CryptoDataPayload {
+            buffer_start: 1,
+            packet_number: 2,
+            data: vec![9; 8],
+        };
+        let packet = CryptoData::new(&shared, nonce, payload.clone());
+
+        let (identity, decoded) = trust.open(&packet, &nonce).unwrap();
+        assert_eq!(identity, peer_pk);
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn open_rejects_untrusted_sender() {
+        let (our_pk, our_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let trust = TrustSet::new(our_sk);
+
+        let shared = encrypt_precompute(&our_pk, &eve_sk);
+        let nonce = gen_nonce();
+        let payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0; 4],
+        };
+        let packet = CryptoData::new(&shared, nonce, payload);
+        assert!(trust.open(&packet, &nonce).is_err());
+    }
+
+    #[test]
+    fn shared_secret_identity_is_trusted() {
+        let (_, our_sk) = gen_keypair();
+        let mut trust = TrustSet::new(our_sk);
+        let identity = trust.trust_shared_secret(b"group passphrase");
+        assert!(trust.is_trusted(&identity));
+        assert!(trust.key_for(&identity).is_some());
+    }
+}
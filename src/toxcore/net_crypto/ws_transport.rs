@@ -0,0 +1,185 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! WebSocket transport for Tox packets.
+
+Many restrictive networks only permit outbound HTTP/WebSocket and block the raw
+UDP/TCP that `CryptoData` (the `0x1b`-tagged packet) and its relatives normally
+ride on. This transport wraps the serialized `ToBytes` output of a packet in a
+binary WebSocket frame to and from a relay endpoint, and unwraps incoming frames
+back into bytes for `FromBytes`. The on-the-wire packet format is unchanged
+*inside* the frame, so it is a drop-in pluggable transport.
+
+A [`Proxy`] bridges WebSocket clients to ordinary UDP peers, letting a node on a
+proxy-only network relay through a cooperating peer that speaks both sides.
+*/
+
+use std::io::{Error, ErrorKind};
+
+use toxcore::binary_io::*;
+use toxcore::dht::packet::crypto_data::MAX_CRYPTO_PACKET_SIZE;
+
+/// Serialize a packet into the binary payload of a WebSocket frame.
+///
+/// The bytes are exactly what the packet's `ToBytes` implementation produces —
+/// the frame adds no Tox-level framing of its own.
+pub fn frame<T: ToBytes>(packet: &T) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; MAX_CRYPTO_PACKET_SIZE];
+    let (_, size) = packet.to_bytes((&mut buf, 0))
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to serialize packet: {:?}", e)))?;
+    buf.truncate(size);
+    Ok(buf)
+}
+
+/// Parse a packet out of the binary payload of a received WebSocket frame.
+pub fn deframe<T: FromBytes>(frame: &[u8]) -> Result<T, Error> {
+    match T::from_bytes(frame) {
+        IResult::Done(_, packet) => Ok(packet),
+        IResult::Incomplete(e) => Err(Error::new(ErrorKind::Other,
+            format!("Incomplete packet in WebSocket frame: {:?}", e))),
+        IResult::Error(e) => Err(Error::new(ErrorKind::Other,
+            format!("Malformed packet in WebSocket frame: {:?}", e))),
+    }
+}
+
+/// A bidirectional binary WebSocket channel to a relay endpoint.
+///
+/// Implemented over whatever concrete WebSocket library is in use, keeping the
+/// transport pluggable and the packet types oblivious to it.
+pub trait WebSocketLink {
+    /// Send one binary WebSocket frame.
+    fn send_binary(&mut self, frame: &[u8]) -> Result<(), Error>;
+    /// Receive the next binary WebSocket frame.
+    fn recv_binary(&mut self) -> Result<Vec<u8>, Error>;
+}
+
+/// A datagram channel to a native UDP peer.
+pub trait UdpLink {
+    /// Send one datagram.
+    fn send_datagram(&mut self, data: &[u8]) -> Result<(), Error>;
+    /// Receive the next datagram.
+    fn recv_datagram(&mut self) -> Result<Vec<u8>, Error>;
+}
+
+/// Packet transport that tunnels serialized packets over a [`WebSocketLink`].
+pub struct WebSocketTransport<L> {
+    link: L,
+}
+
+impl<L: WebSocketLink> WebSocketTransport<L> {
+    /// Wrap a WebSocket link as a packet transport.
+    pub fn new(link: L) -> WebSocketTransport<L> {
+        WebSocketTransport { link }
+    }
+
+    /// Send a packet, framing it as a binary WebSocket message.
+    pub fn send<T: ToBytes>(&mut self, packet: &T) -> Result<(), Error> {
+        let frame = frame(packet)?;
+        self.link.send_binary(&frame)
+    }
+
+    /// Receive the next packet, unwrapping it from a binary WebSocket message.
+    pub fn recv<T: FromBytes>(&mut self) -> Result<T, Error> {
+        let frame = self.link.recv_binary()?;
+        deframe(&frame)
+    }
+
+    /// Borrow the underlying link.
+    pub fn link_mut(&mut self) -> &mut L {
+        &mut self.link
+    }
+}
+
+/// Bridge that relays between a WebSocket client and a native UDP peer.
+///
+/// Frames arriving from the WebSocket side are unwrapped and forwarded verbatim
+/// as UDP datagrams; datagrams from the UDP side are wrapped in binary frames
+/// and forwarded to the WebSocket client. The packet bytes are never reparsed,
+/// so the proxy is agnostic to which Tox packet type is being relayed.
+pub struct Proxy<W, U> {
+    ws: W,
+    udp: U,
+}
+
+impl<W: WebSocketLink, U: UdpLink> Proxy<W, U> {
+    /// Create a proxy bridging `ws` and `udp`.
+    pub fn new(ws: W, udp: U) -> Proxy<W, U> {
+        Proxy { ws, udp }
+    }
+
+    /// Forward one frame from the WebSocket client to the UDP peer.
+    pub fn pump_ws_to_udp(&mut self) -> Result<(), Error> {
+        let frame = self.ws.recv_binary()?;
+        self.udp.send_datagram(&frame)
+    }
+
+    /// Forward one datagram from the UDP peer to the WebSocket client.
+    pub fn pump_udp_to_ws(&mut self) -> Result<(), Error> {
+        let datagram = self.udp.recv_datagram()?;
+        self.ws.send_binary(&datagram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use toxcore::dht::packet::crypto_data::CryptoData;
+
+    /// In-memory loopback link used to exercise framing.
+    #[derive(Default)]
+    struct LoopbackLink {
+        frames: VecDeque<Vec<u8>>,
+    }
+
+    impl WebSocketLink for LoopbackLink {
+        fn send_binary(&mut self, frame: &[u8]) -> Result<(), Error> {
+            self.frames.push_back(frame.to_vec());
+            Ok(())
+        }
+        fn recv_binary(&mut self) -> Result<Vec<u8>, Error> {
+            self.frames.pop_front()
+                .ok_or_else(|| Error::new(ErrorKind::WouldBlock, "No frame available"))
+        }
+    }
+
+    #[test]
+    fn frame_deframe_roundtrip() {
+        let packet = CryptoData {
+            nonce_last_bytes: 42,
+            payload: vec![7; 32],
+        };
+        let bytes = frame(&packet).unwrap();
+        let decoded: CryptoData = deframe(&bytes).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn transport_send_recv_roundtrip() {
+        let packet = CryptoData {
+            nonce_last_bytes: 1,
+            payload: vec![3; 16],
+        };
+        let mut transport = WebSocketTransport::new(LoopbackLink::default());
+        transport.send(&packet).unwrap();
+        let decoded: CryptoData = transport.recv().unwrap();
+        assert_eq!(decoded, packet);
+    }
+}